@@ -1,15 +1,22 @@
-use std::num::ParseIntError;
+use std::num::{ParseFloatError, ParseIntError};
 use std::path::Path;
 use std::fs::File;
-use std::io::{self, Read, BufRead};
-use std::collections::BTreeMap;
+use std::io::{self, Read, BufRead, Write};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 
 use tflite::ops::builtin::BuiltinOpResolver;
-use tflite::{FlatBufferModel, InterpreterBuilder};
+use tflite::{ElementKind, FlatBufferModel, InterpreterBuilder};
 
 use image::imageops::FilterType;
+use image::RgbImage;
+
+use imageproc::drawing::{draw_hollow_rect_mut, draw_text_mut};
+use imageproc::rect::Rect;
+use rusttype::{Font, Scale};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about=None)]
@@ -20,11 +27,116 @@ struct Args {
     label: Option<String>,
     #[arg(default_value="-", help="Image file")]
     image: String,
+    #[arg(long, default_value="127.5", help="Mean value subtracted from each pixel before dividing by input-std (float input models only)")]
+    input_mean: f32,
+    #[arg(long, default_value="127.5", help="Value each pixel is divided by after subtracting input-mean (float input models only)")]
+    input_std: f32,
+    #[arg(long, value_enum, default_value="detect", help="Output tensor convention: SSD detection (boxes/classes/scores/count) or a single per-class score vector")]
+    mode: Mode,
+    #[arg(long, default_value="5", help="Number of top scoring classes to print in classify mode")]
+    top_k: usize,
+    #[arg(long, default_value="1", help="Number of timed invoke() calls of the interpreter, run after any --warmup invocations, for benchmarking")]
+    count: u32,
+    #[arg(long, help="Number of interpreter threads to use")]
+    threads: Option<i32>,
+    #[arg(long, default_value="0", help="Number of initial invocations to discard before collecting latency stats")]
+    warmup: u32,
+    #[arg(long, help="Bypass image loading and feed this pre-normalized tensor data directly into the input tensor")]
+    raw_input: Option<String>,
+    #[arg(long, value_enum, default_value="text", help="Format of --raw-input and --dump-output: newline-separated floats, or a little-endian f32 binary blob")]
+    raw_format: RawFormat,
+    #[arg(long, help="Write every output tensor's raw f32 values to this path")]
+    dump_output: Option<String>,
+    #[arg(long, help="Draw detection boxes onto the source image and write the annotated result here (format inferred from the extension)")]
+    draw: Option<String>,
+    #[arg(long, help="TrueType/OpenType font used to render detection labels with --draw; boxes are still drawn without it")]
+    font: Option<String>,
+    #[arg(long, help="Resize preserving aspect ratio and pad to the input size instead of stretching")]
+    letterbox: bool,
+    #[arg(long, default_value="114", help="Fill value (0-255, applied to all RGB channels) for the letterbox padding")]
+    letterbox_fill: u8,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+enum Mode {
+    Detect,
+    Classify,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum RawFormat {
+    Text,
+    Bin,
+}
+
+fn normalize_image(img: &RgbImage, mean: f32, std: f32) -> Vec<f32> {
+    img.as_raw().iter().map(|&p| (p as f32 - mean) / std).collect()
+}
+
+struct ScoredClass {
+    index: usize,
+    score: f32,
+}
+
+impl PartialEq for ScoredClass {
+    fn eq(&self, other: &Self) -> bool {
+        self.score == other.score
+    }
+}
+
+impl Eq for ScoredClass {}
+
+impl PartialOrd for ScoredClass {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredClass {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.score.partial_cmp(&other.score).unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
+fn top_k(scores: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let mut heap: BinaryHeap<Reverse<ScoredClass>> = BinaryHeap::with_capacity(k);
+    for (index, &score) in scores.iter().enumerate() {
+        if heap.len() < k {
+            heap.push(Reverse(ScoredClass { index, score }));
+        } else if let Some(Reverse(min)) = heap.peek() {
+            if score > min.score {
+                heap.pop();
+                heap.push(Reverse(ScoredClass { index, score }));
+            }
+        }
+    }
+    let mut result: Vec<(usize, f32)> = heap.into_iter().map(|Reverse(c)| (c.index, c.score)).collect();
+    result.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    result
+}
+
+fn report_latency_stats(durations: &mut [Duration]) {
+    durations.sort();
+
+    let to_ms = |d: Duration| d.as_secs_f64() * 1e3;
+    let percentile = |p: f64| {
+        let index = (((durations.len() - 1) as f64) * p).round() as usize;
+        to_ms(durations[index])
+    };
+
+    let min = to_ms(durations[0]);
+    let max = to_ms(durations[durations.len() - 1]);
+    let mean = durations.iter().map(|d| to_ms(*d)).sum::<f64>() / durations.len() as f64;
+
+    eprintln!("count: {}, min: {:.3}ms, max: {:.3}ms, mean: {:.3}ms, p50: {:.3}ms, p90: {:.3}ms",
+        durations.len(), min, max, mean, percentile(0.5), percentile(0.9));
 }
 
 #[derive(Debug)]
 enum Error {
     InvalidModel,
+    InvalidRawInput(ParseFloatError),
+    RawSizeMismatch { expected: usize, actual: usize },
     InvalidLabel(ParseIntError),
     TFLiteError(tflite::Error),
     ImageError(image::error::ImageError),
@@ -57,6 +169,149 @@ impl From<ParseIntError> for Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+fn read_raw_input<P: AsRef<Path>>(path: P, format: RawFormat, expected_len: usize) -> Result<Vec<f32>> {
+    let data = match format {
+        RawFormat::Text => {
+            let file = File::open(path)?;
+            let mut values = Vec::new();
+            for line in io::BufReader::new(file).lines() {
+                let line = line?;
+                let value = line.trim().parse::<f32>().map_err(Error::InvalidRawInput)?;
+                values.push(value);
+            }
+            values
+        },
+        RawFormat::Bin => {
+            let mut buf = Vec::new();
+            File::open(path)?.read_to_end(&mut buf)?;
+            if buf.len() % 4 != 0 {
+                return Err(Error::RawSizeMismatch { expected: expected_len * 4, actual: buf.len() });
+            }
+            buf.chunks_exact(4).map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap())).collect()
+        },
+    };
+    if data.len() != expected_len {
+        return Err(Error::RawSizeMismatch { expected: expected_len, actual: data.len() });
+    }
+    Ok(data)
+}
+
+fn write_raw_output<P: AsRef<Path>>(path: P, format: RawFormat, data: &[f32]) -> Result<()> {
+    let mut file = File::create(path)?;
+    match format {
+        RawFormat::Text => {
+            for value in data {
+                writeln!(file, "{}", value)?;
+            }
+        },
+        RawFormat::Bin => {
+            for value in data {
+                file.write_all(&value.to_le_bytes())?;
+            }
+        },
+    }
+    Ok(())
+}
+
+struct Letterbox {
+    scale: f32,
+    pad_x: f32,
+    pad_y: f32,
+}
+
+impl Letterbox {
+    fn unmap(&self, x: f32, y: f32) -> (f32, f32) {
+        ((x - self.pad_x) / self.scale, (y - self.pad_y) / self.scale)
+    }
+}
+
+fn letterbox_resize(img: &image::DynamicImage, target_width: u32, target_height: u32, fill: u8) -> (RgbImage, Letterbox) {
+    let (src_width, src_height) = (img.width() as f32, img.height() as f32);
+    let scale = (target_width as f32 / src_width).min(target_height as f32 / src_height);
+    let new_width = (src_width * scale).round() as u32;
+    let new_height = (src_height * scale).round() as u32;
+    let resized = img.resize_exact(new_width, new_height, FilterType::Lanczos3).to_rgb8();
+
+    let pad_x = (target_width - new_width) / 2;
+    let pad_y = (target_height - new_height) / 2;
+
+    let mut canvas = RgbImage::from_pixel(target_width, target_height, image::Rgb([fill, fill, fill]));
+    image::imageops::overlay(&mut canvas, &resized, pad_x as i64, pad_y as i64);
+
+    (canvas, Letterbox { scale, pad_x: pad_x as f32, pad_y: pad_y as f32 })
+}
+
+struct DrawOptions<'a> {
+    threshold: f32,
+    font: Option<&'a Font<'a>>,
+    input_size: (u32, u32),
+    letterbox: Option<&'a Letterbox>,
+}
+
+fn draw_detections(
+    original: &image::DynamicImage,
+    boxes: &[f32],
+    classes: &[f32],
+    scores: &[f32],
+    count: usize,
+    labels: Option<&BTreeMap<u32, String>>,
+    options: &DrawOptions,
+) -> RgbImage {
+    let mut canvas = original.to_rgb8();
+    let (width, height) = (canvas.width() as f32, canvas.height() as f32);
+    let (input_width, input_height) = (options.input_size.0 as f32, options.input_size.1 as f32);
+    const COLOR: image::Rgb<u8> = image::Rgb([255, 0, 0]);
+
+    for i in 0..count {
+        if scores[i] < options.threshold {
+            continue;
+        }
+        let (xmin, ymin, xmax, ymax) = match options.letterbox {
+            Some(letterbox) => {
+                let (xmin, ymin) = letterbox.unmap(boxes[i * 4 + 1] * input_width, boxes[i * 4] * input_height);
+                let (xmax, ymax) = letterbox.unmap(boxes[i * 4 + 3] * input_width, boxes[i * 4 + 2] * input_height);
+                (xmin, ymin, xmax, ymax)
+            },
+            None => (
+                boxes[i * 4 + 1] * width, boxes[i * 4] * height,
+                boxes[i * 4 + 3] * width, boxes[i * 4 + 2] * height,
+            ),
+        };
+
+        let rect = Rect::at(xmin as i32, ymin as i32)
+            .of_size((xmax - xmin).max(1.0) as u32, (ymax - ymin).max(1.0) as u32);
+        draw_hollow_rect_mut(&mut canvas, rect, COLOR);
+
+        let klass = classes[i] as u32;
+        let klass_id = klass.to_string();
+        let klass = labels.and_then(|labels| labels.get(&klass)).unwrap_or(&klass_id);
+        if let Some(font) = options.font {
+            let text = format!("{}: {:.2}", klass, scores[i]);
+            let text_y = (ymin as i32 - 14).max(0);
+            draw_text_mut(&mut canvas, COLOR, xmin as i32, text_y, Scale::uniform(14.0), font, &text);
+        }
+    }
+
+    canvas
+}
+
+fn save_image<P: AsRef<Path>>(path: P, img: &RgbImage) -> Result<()> {
+    let path = path.as_ref();
+    let is_tiff = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"))
+        .unwrap_or(false);
+
+    if is_tiff {
+        let file = File::create(path)?;
+        image::codecs::tiff::TiffEncoder::new(file)
+            .encode(img.as_raw(), img.width(), img.height(), image::ColorType::Rgb8)?;
+    } else {
+        img.save(path)?;
+    }
+    Ok(())
+}
+
 fn load_labels<P: AsRef<Path>>(path: P) -> Result<BTreeMap<u32, String>> {
     let mut labels = BTreeMap::new();
     let file = File::open(path)?;
@@ -82,6 +337,9 @@ fn main() -> Result<()> {
     let resolver = BuiltinOpResolver::default();
     let builder = InterpreterBuilder::new(&model, &resolver)?;
     let mut interpreter = builder.build()?;
+    if let Some(threads) = args.threads {
+        interpreter.set_num_threads(threads);
+    }
     interpreter.allocate_tensors()?;
 
     let inputs = interpreter.inputs().to_vec();
@@ -90,34 +348,145 @@ fn main() -> Result<()> {
     let input_height = input_tensor.dims[1] as u32;
     let input_width = input_tensor.dims[2] as u32;
 
-    let img = match args.image.as_str() {
-        "-" => {
-            let mut buf = Vec::new();
-            io::stdin().read_to_end(&mut buf)?;
-            image::load_from_memory(&buf)
+    let mut original_image = None;
+    let mut letterbox = None;
+    match &args.raw_input {
+        Some(path) => {
+            let input_len: usize = input_tensor.dims.iter().product();
+            let data = read_raw_input(path, args.raw_format, input_len)?;
+            interpreter.tensor_data_mut(inputs[0])?.copy_from_slice(&data);
         },
-        _ => image::open(&args.image),
-    }?.resize_exact(input_width, input_height, FilterType::Lanczos3).to_rgb8();
+        None => {
+            let loaded = match args.image.as_str() {
+                "-" => {
+                    let mut buf = Vec::new();
+                    io::stdin().read_to_end(&mut buf)?;
+                    image::load_from_memory(&buf)
+                },
+                _ => image::open(&args.image),
+            }?;
+            let img = if args.letterbox {
+                let (canvas, transform) = letterbox_resize(&loaded, input_width, input_height, args.letterbox_fill);
+                letterbox = Some(transform);
+                canvas
+            } else {
+                loaded.resize_exact(input_width, input_height, FilterType::Lanczos3).to_rgb8()
+            };
 
-    interpreter.tensor_data_mut(inputs[0])?.copy_from_slice(&img);
-    interpreter.invoke()?;
+            match input_tensor.element_kind {
+                ElementKind::Float32 => {
+                    let normalized = normalize_image(&img, args.input_mean, args.input_std);
+                    interpreter.tensor_data_mut(inputs[0])?.copy_from_slice(&normalized);
+                },
+                _ => {
+                    interpreter.tensor_data_mut(inputs[0])?.copy_from_slice(&img);
+                },
+            }
+            original_image = Some(loaded);
+        },
+    }
 
-    //let boxes: &[f32] = interpreter.tensor_data(outputs[0])?;
-    let classes: &[f32] = interpreter.tensor_data(outputs[1])?;
-    let scores: &[f32] = interpreter.tensor_data(outputs[2])?;
-    let count = interpreter.tensor_data::<f32>(outputs[3])?[0] as usize;
+    let mut durations = Vec::with_capacity(args.count as usize);
+    for i in 0..args.warmup + args.count {
+        let start = Instant::now();
+        interpreter.invoke()?;
+        if i >= args.warmup {
+            durations.push(start.elapsed());
+        }
+    }
+    if !durations.is_empty() {
+        report_latency_stats(&mut durations);
+    }
 
-    const THRESHOLD: f32 = 0.5;
-    for i in 0..count {
-        if scores[i] >= THRESHOLD {
-            let klass = classes[i] as u32;
-            let klass_id = klass.to_string();
-            let klass = labels.as_ref().and_then(|labels| labels.get(&klass)).unwrap_or(&klass_id);
-            let score = scores[i];
-            println!("class: {}, score: {}", klass, score);
+    if let Some(path) = &args.dump_output {
+        let mut dump = Vec::new();
+        for &output in &outputs {
+            dump.extend_from_slice(interpreter.tensor_data::<f32>(output)?);
         }
+        write_raw_output(path, args.raw_format, &dump)?;
+    }
+
+    match args.mode {
+        Mode::Detect => {
+            let boxes: &[f32] = interpreter.tensor_data(outputs[0])?;
+            let classes: &[f32] = interpreter.tensor_data(outputs[1])?;
+            let scores: &[f32] = interpreter.tensor_data(outputs[2])?;
+            let count = interpreter.tensor_data::<f32>(outputs[3])?[0] as usize;
+
+            const THRESHOLD: f32 = 0.5;
+            for i in 0..count {
+                if scores[i] >= THRESHOLD {
+                    let klass = classes[i] as u32;
+                    let klass_id = klass.to_string();
+                    let klass = labels.as_ref().and_then(|labels| labels.get(&klass)).unwrap_or(&klass_id);
+                    let score = scores[i];
+                    println!("class: {}, score: {}", klass, score);
+                }
+            }
+
+            if let Some(draw_path) = &args.draw {
+                if let Some(original) = &original_image {
+                    let font = args.font.as_ref()
+                        .and_then(|path| std::fs::read(path).ok())
+                        .and_then(Font::try_from_vec);
+                    let options = DrawOptions {
+                        threshold: THRESHOLD,
+                        font: font.as_ref(),
+                        input_size: (input_width, input_height),
+                        letterbox: letterbox.as_ref(),
+                    };
+                    let annotated = draw_detections(original, boxes, classes, scores, count, labels.as_ref(), &options);
+                    save_image(draw_path, &annotated)?;
+                }
+            }
+        },
+        Mode::Classify => {
+            let scores: &[f32] = interpreter.tensor_data(outputs[0])?;
+            for (index, score) in top_k(scores, args.top_k) {
+                let klass = index as u32;
+                let klass_id = klass.to_string();
+                let klass = labels.as_ref().and_then(|labels| labels.get(&klass)).unwrap_or(&klass_id);
+                println!("{}: {}", klass, score);
+            }
+        },
     }
 
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_k_zero_returns_nothing() {
+        assert!(top_k(&[0.1, 0.5, 0.9], 0).is_empty());
+    }
+
+    #[test]
+    fn top_k_larger_than_scores_returns_all_sorted_descending() {
+        let scores = [0.3, 0.9, 0.1];
+        assert_eq!(top_k(&scores, 10), vec![(1, 0.9), (0, 0.3), (2, 0.1)]);
+    }
+
+    #[test]
+    fn top_k_breaks_ties_without_dropping_entries() {
+        let scores = [0.5, 0.5, 0.5, 0.2];
+        let result = top_k(&scores, 2);
+        assert_eq!(result.len(), 2);
+        assert!(result.iter().all(|&(_, score)| score == 0.5));
+    }
+
+    #[test]
+    fn letterbox_unmap_round_trips_a_forward_mapped_point() {
+        let letterbox = Letterbox { scale: 0.5, pad_x: 10.0, pad_y: 20.0 };
+        let (orig_x, orig_y) = (40.0, 60.0);
+        let (canvas_x, canvas_y) = (orig_x * letterbox.scale + letterbox.pad_x, orig_y * letterbox.scale + letterbox.pad_y);
+
+        let (x, y) = letterbox.unmap(canvas_x, canvas_y);
+
+        assert!((x - orig_x).abs() < 1e-4);
+        assert!((y - orig_y).abs() < 1e-4);
+    }
+}
+